@@ -1,9 +1,10 @@
 use bytemuck::{__core::time::Duration, bytes_of, Pod, Zeroable};
+use flate2::{write::ZlibEncoder, Compression};
 use indicatif::{ProgressBar, ProgressStyle};
 use serial::{BaudRate, SerialPort};
 use strum_macros::Display;
 
-use std::{mem::size_of, thread::sleep};
+use std::{io::Write, mem::size_of, path::Path, thread::sleep};
 
 use crate::{
     chip::Chip, connection::Connection, elf::FirmwareImage, encoder::SlipEncoder, error::RomError,
@@ -17,6 +18,23 @@ const FLASH_SECTOR_SIZE: usize = 0x1000;
 const FLASH_BLOCK_SIZE: usize = 0x100;
 const FLASH_SECTORS_PER_BLOCK: usize = FLASH_SECTOR_SIZE / FLASH_BLOCK_SIZE;
 const FLASH_WRITE_SIZE: usize = 0x400;
+// The stub accepts much larger flash write blocks than the bare ROM loader.
+const STUB_FLASH_WRITE_SIZE: usize = 0x4000;
+
+// SLIP frame the stub emits once it is up and listening.
+const STUB_HELLO: &[u8] = b"OHAI";
+
+// SPI flash opcodes used by the bare-ROM erase fallback.
+const SPI_FLASH_WRITE_ENABLE: u8 = 0x06;
+const SPI_FLASH_READ_STATUS: u8 = 0x05;
+const SPI_FLASH_SECTOR_ERASE: u8 = 0x20;
+const SPI_FLASH_BLOCK_ERASE: u8 = 0xD8;
+const FLASH_BLOCK_ERASE_SIZE: usize = FLASH_SECTOR_SIZE * 16;
+
+// SPI read-data opcode, and the number of bytes that fit in the `w0`..`wN` data-register
+// window for a single MISO transfer.
+const SPI_FLASH_READ_CMD: u8 = 0x03;
+const MAX_SPI_READ_SIZE: usize = 64;
 
 // register used for chip detect
 const CHIP_DETECT_MAGIC_REG_ADDR: u32 = 0x40001000;
@@ -43,6 +61,12 @@ enum Command {
     SpiSetParams = 0x0B,
     SpiAttach = 0x0D,
     ChangeBaud = 0x0F,
+    FlashDeflBegin = 0x10,
+    FlashDeflData = 0x11,
+    FlashDeflEnd = 0x12,
+    FlashMd5 = 0x13,
+    EraseFlash = 0xD0,
+    EraseRegion = 0xD1,
 }
 
 impl Command {
@@ -63,8 +87,13 @@ impl Command {
             )
         }
         match self {
-            Command::FlashBegin => calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size),
-            Command::FlashData => calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size),
+            Command::FlashBegin
+            | Command::FlashDeflBegin
+            | Command::EraseFlash
+            | Command::EraseRegion => calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size),
+            Command::FlashData | Command::FlashDeflData => {
+                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size)
+            }
             _ => self.timeout(),
         }
     }
@@ -92,6 +121,20 @@ pub enum FlashSize {
 }
 
 impl FlashSize {
+    /// The flash capacity in bytes.
+    fn size(self) -> u32 {
+        match self {
+            FlashSize::Flash256Kb => 0x0004_0000,
+            FlashSize::Flash512Kb => 0x0008_0000,
+            FlashSize::Flash1Mb => 0x0010_0000,
+            FlashSize::Flash2Mb => 0x0020_0000,
+            FlashSize::Flash4Mb => 0x0040_0000,
+            FlashSize::Flash8Mb => 0x0080_0000,
+            FlashSize::Flash16Mb => 0x0100_0000,
+            FlashSize::FlashRetry => 0,
+        }
+    }
+
     fn from(value: u8) -> Result<FlashSize, Error> {
         match value {
             0x12 => Ok(FlashSize::Flash256Kb),
@@ -184,6 +227,22 @@ struct WriteRegParams {
     delay_us: u32,
 }
 
+#[derive(Zeroable, Pod, Copy, Clone, Debug)]
+#[repr(C)]
+struct EraseRegionParams {
+    offset: u32,
+    size: u32,
+}
+
+#[derive(Zeroable, Pod, Copy, Clone, Debug)]
+#[repr(C)]
+struct Md5Params {
+    addr: u32,
+    size: u32,
+    dummy1: u32,
+    dummy2: u32,
+}
+
 #[derive(Zeroable, Pod, Copy, Clone)]
 #[repr(C)]
 struct EntryParams {
@@ -191,29 +250,55 @@ struct EntryParams {
     entry: u32,
 }
 
+/// A small helper program uploaded into device RAM at connect time.
+///
+/// Talking to the stub instead of the bare ROM unlocks higher throughput and commands
+/// (erase-region, read-flash, md5) that are missing from some ROM revisions.
+pub struct Stub {
+    entry: u32,
+    text: u32,
+    text_data: Vec<u8>,
+    data: u32,
+    data_data: Vec<u8>,
+}
+
+impl Stub {
+    /// The RAM segments to upload, paired with their load address.
+    fn segments(&self) -> [(u32, &[u8]); 2] {
+        [(self.text, &self.text_data), (self.data, &self.data_data)]
+    }
+}
+
 pub struct Flasher {
     connection: Connection,
     chip: Chip,
     flash_size: FlashSize,
     spi_params: SpiAttachParams,
+    stub: bool,
 }
 
 impl Flasher {
     pub fn connect(
         serial: impl SerialPort + 'static,
         speed: Option<BaudRate>,
+        stub: bool,
     ) -> Result<Self, Error> {
         let mut flasher = Flasher {
             connection: Connection::new(serial), // default baud is always 115200
             chip: Chip::Esp8266,                 // dummy, set properly later
             flash_size: FlashSize::Flash4Mb,
             spi_params: SpiAttachParams::default(), // may be set when trying to attach to flash
+            stub: false,                            // set once the stub is actually running
         };
         flasher.start_connection()?;
         flasher.connection.set_timeout(DEFAULT_TIMEOUT)?;
         flasher.chip_detect()?;
         flasher.spi_autodetect()?;
 
+        if stub {
+            flasher.run_stub()?;
+        }
+
         if let Some(b) = speed {
             match flasher.chip {
                 Chip::Esp8266 => (), /* Not available */
@@ -371,7 +456,7 @@ impl Flasher {
                     (length as u16, |encoder: &mut Encoder| {
                         encoder.write(bytes_of(&params))?;
                         encoder.write(data)?;
-                        let padding = &[padding_byte; FLASH_WRITE_SIZE][0..padding];
+                        let padding = &[padding_byte; STUB_FLASH_WRITE_SIZE][0..padding];
                         encoder.write(padding)?;
                         Ok(())
                     }),
@@ -399,6 +484,62 @@ impl Flasher {
             })
     }
 
+    fn flash_defl_finish(&mut self, reboot: bool) -> Result<(), Error> {
+        self.connection
+            .with_timeout(Command::FlashDeflEnd.timeout(), |connection| {
+                connection.write_command(Command::FlashDeflEnd as u8, &[(!reboot) as u8][..], 0)
+            })
+    }
+
+    /// Ask the device to compute the MD5 digest of a flash region.
+    ///
+    /// The ROM loader returns the 16 raw digest bytes in the response body, whereas the stub
+    /// returns them as 32 ASCII hex characters, so the length is used to decide how to parse.
+    fn flash_md5(&mut self, addr: u32, size: u32) -> Result<[u8; 16], Error> {
+        let params = Md5Params {
+            addr,
+            size,
+            dummy1: 0,
+            dummy2: 0,
+        };
+
+        let body = self
+            .connection
+            .with_timeout(Command::FlashMd5.timeout_for_size(size), |connection| {
+                connection.write_command(Command::FlashMd5 as u8, bytes_of(&params), 0)?;
+
+                for _ in 0..100 {
+                    if let Some(response) = connection.read_response()? {
+                        if response.return_op == Command::FlashMd5 as u8 {
+                            if response.status == 1 {
+                                return Err(Error::RomError(RomError::from(response.error)));
+                            }
+                            return Ok(response.data);
+                        }
+                    }
+                }
+
+                Err(Error::Timeout)
+            })?;
+
+        let mut digest = [0u8; 16];
+        match body.len() {
+            // ROM loader: the digest is returned as raw bytes.
+            16 => digest.copy_from_slice(&body[..16]),
+            // Stub loader: the digest is returned as 32 ASCII hex characters.
+            32 => {
+                for (i, byte) in digest.iter_mut().enumerate() {
+                    let hex = std::str::from_utf8(&body[i * 2..i * 2 + 2])
+                        .map_err(|_| Error::VerifyFailed)?;
+                    *byte = u8::from_str_radix(hex, 16).map_err(|_| Error::VerifyFailed)?;
+                }
+            }
+            _ => return Err(Error::VerifyFailed),
+        }
+
+        Ok(digest)
+    }
+
     fn enable_flash(&mut self, spi_attach_params: SpiAttachParams) -> Result<(), Error> {
         match self.chip {
             Chip::Esp8266 => {
@@ -415,10 +556,18 @@ impl Flasher {
         Ok(())
     }
 
-    fn spi_command(&mut self, command: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
-        assert!(read_bits < 32);
-        assert!(data.len() < 64);
-
+    /// Drive one SPI user transfer: send `command` plus the `data` MOSI payload, then read
+    /// `read_words` data words back out of the `w0`..`wN` window.
+    ///
+    /// This is the shared register dance behind [`Flasher::spi_command`] (single-word reads)
+    /// and [`Flasher::spi_read_block`] (multi-word reads).
+    fn spi_transfer(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        read_bits: u32,
+        read_words: usize,
+    ) -> Result<Vec<u32>, Error> {
         let spi_registers = self.chip.spi_registers();
 
         let old_spi_usr = self.read_reg(spi_registers.usr())?;
@@ -460,8 +609,8 @@ impl Flasher {
             for (i, bytes) in data.chunks(4).enumerate() {
                 let mut data_bytes = [0; 4];
                 data_bytes[0..bytes.len()].copy_from_slice(bytes);
-                let data = u32::from_le_bytes(data_bytes);
-                self.write_reg(spi_registers.w0() + i as u32, data, None)?;
+                let word = u32::from_le_bytes(data_bytes);
+                self.write_reg(spi_registers.w0() + i as u32, word, None)?;
             }
         }
 
@@ -479,13 +628,55 @@ impl Flasher {
             }
         }
 
-        let result = self.read_reg(spi_registers.w0())?;
+        let mut result = Vec::with_capacity(read_words);
+        for word in 0..read_words as u32 {
+            result.push(self.read_reg(spi_registers.w0() + word)?);
+        }
+
         self.write_reg(spi_registers.usr(), old_spi_usr, None)?;
         self.write_reg(spi_registers.usr2(), old_spi_usr2, None)?;
 
         Ok(result)
     }
 
+    fn spi_command(&mut self, command: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
+        assert!(read_bits < 32);
+        assert!(data.len() < 64);
+
+        let read_words = if read_bits > 0 { 1 } else { 0 };
+        let result = self.spi_transfer(command, data, read_bits, read_words)?;
+        Ok(result.first().copied().unwrap_or(0))
+    }
+
+    /// Drive a single SPI transfer that issues the flash read-data opcode followed by a 24-bit
+    /// big-endian address and reads `length` bytes back out of the `w0`..`wN` MISO window.
+    ///
+    /// Unlike [`Flasher::spi_command`], which only surfaces the first data word, this reads as
+    /// many words of the register window as `length` needs (the whole window for
+    /// `MAX_SPI_READ_SIZE` bytes), so several dozen bytes can be fetched per round-trip.
+    fn spi_read_block(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        assert!(length <= MAX_SPI_READ_SIZE);
+
+        // 24-bit address, most-significant byte first.
+        let addr = address.to_be_bytes();
+        let word_count = (length + 3) / 4;
+
+        let words = self.spi_transfer(
+            SPI_FLASH_READ_CMD,
+            &addr[1..4],
+            length as u32 * 8,
+            word_count,
+        )?;
+
+        let mut result = Vec::with_capacity(word_count * 4);
+        for word in words {
+            result.extend_from_slice(&word.to_le_bytes());
+        }
+        result.truncate(length);
+
+        Ok(result)
+    }
+
     fn read_reg(&mut self, reg: u32) -> Result<u32, Error> {
         self.connection
             .with_timeout(Command::ReadReg.timeout(), |connection| {
@@ -550,53 +741,226 @@ impl Flasher {
         Ok(())
     }
 
+    /// Upload the per-chip stub into RAM and hand control over to it.
+    ///
+    /// The segments are loaded with the same `MemBegin`/`MemData`/`MemEnd` path used by
+    /// [`Flasher::load_elf_to_ram`]. Once running, the stub announces itself with an `OHAI`
+    /// frame; after that the enhanced command set and larger block sizes are available.
+    pub fn run_stub(&mut self) -> Result<(), Error> {
+        let stub = self.chip.stub().ok_or(Error::StubNotAvailable)?;
+
+        for (addr, data) in stub.segments() {
+            let padding = (4 - data.len() % 4) % 4;
+            let block_count =
+                (data.len() + padding + MAX_RAM_BLOCK_SIZE - 1) / MAX_RAM_BLOCK_SIZE;
+            self.begin_command(
+                Command::MemBegin,
+                data.len() as u32,
+                block_count as u32,
+                MAX_RAM_BLOCK_SIZE as u32,
+                addr,
+            )?;
+
+            for (i, block) in data.chunks(MAX_RAM_BLOCK_SIZE).enumerate() {
+                let block_padding = if i == block_count - 1 { padding } else { 0 };
+                self.block_command(Command::MemData, block, block_padding, 0, i as u32)?;
+            }
+        }
+
+        self.mem_finish(stub.entry)?;
+
+        // Wait for the stub to come up and greet us before trusting the new command set.
+        self.connection
+            .with_timeout(DEFAULT_TIMEOUT, |connection| {
+                for _ in 0..100 {
+                    if let Some(response) = connection.read_response()? {
+                        if response.data == STUB_HELLO {
+                            return Ok(());
+                        }
+                    }
+                }
+
+                Err(Error::ConnectionFailed)
+            })?;
+
+        self.stub = true;
+        // The stub uses a different response status layout, so the connection needs to know.
+        self.connection.set_stub(true);
+
+        Ok(())
+    }
+
+    /// The flash write block size for the active loader. The stub accepts larger blocks than
+    /// the bare ROM, cutting the number of round-trips per segment.
+    fn flash_write_size(&self) -> usize {
+        if self.stub {
+            STUB_FLASH_WRITE_SIZE
+        } else {
+            FLASH_WRITE_SIZE
+        }
+    }
+
     /// Load an elf image to flash and execute it
+    ///
+    /// When `compress` is set each segment is zlib-compressed and streamed with the
+    /// `FlashDefl*` commands, which is considerably faster over serial. The ESP8266 ROM
+    /// does not implement deflate, so it always falls back to the uncompressed path.
     pub fn load_elf_to_flash(
         &mut self,
         elf_data: &[u8],
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
+        compress: bool,
+        force: bool,
     ) -> Result<(), Error> {
         self.enable_flash(self.spi_params)?;
 
         let mut image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
         image.flash_size = self.flash_size();
 
+        // The ESP8266 ROM lacks deflate support, fall back to the raw write path there.
+        let compress = compress && self.chip != Chip::Esp8266;
+
+        let mut wrote = false;
         for segment in self
             .chip
             .get_flash_segments(&image, bootloader, partition_table)
         {
             let segment = segment?;
-            let addr = segment.addr;
-            let block_count = (segment.data.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
+            wrote |= self.write_segment(segment.addr, &segment.data, compress, force)?;
+        }
 
-            let erase_size = match self.chip {
-                Chip::Esp8266 => get_erase_size(addr as usize, segment.data.len()) as u32,
-                _ => segment.data.len() as u32,
-            };
+        // An END with no preceding BEGIN can be rejected, so only finish when we actually
+        // wrote a segment. The device is still reset either way so it boots the image.
+        if wrote {
+            if compress {
+                self.flash_defl_finish(false)?;
+            } else {
+                self.flash_finish(false)?;
+            }
+        }
+
+        self.connection.reset()?;
+
+        Ok(())
+    }
+
+    /// Write a raw binary blob to flash at a fixed offset.
+    ///
+    /// This covers workflows that do not involve an ELF wrapper, such as writing a prebuilt
+    /// bootloader, a standalone partition table, or a filesystem image.
+    pub fn write_bin_to_flash(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        self.write_bins_to_flash(std::iter::once((offset, data)))
+    }
+
+    /// Write several raw binary blobs to flash, each at its own offset, in a single session.
+    pub fn write_bins_to_flash<'a>(
+        &mut self,
+        images: impl IntoIterator<Item = (u32, &'a [u8])>,
+    ) -> Result<(), Error> {
+        self.enable_flash(self.spi_params)?;
+
+        let mut wrote = false;
+        for (offset, data) in images {
+            wrote |= self.write_segment(offset, data, false, false)?;
+        }
+
+        if wrote {
+            self.flash_finish(false)?;
+        }
+        self.connection.reset()?;
 
+        Ok(())
+    }
+
+    /// Whether the active loader implements the on-device MD5 command.
+    ///
+    /// `SPI_FLASH_MD5` (0x13) is part of the stub command set; the ESP8266 *bare ROM* does not
+    /// provide it, so md5-based skip/verify has to be gated off there.
+    fn supports_md5(&self) -> bool {
+        self.stub || self.chip != Chip::Esp8266
+    }
+
+    /// Write a single segment to flash at `addr`, sharing the begin/block/checksum/progress
+    /// and md5 verification used by both ELF and raw-binary flashing.
+    ///
+    /// Returns `true` when the segment was actually written and `false` when it was skipped
+    /// because the on-device contents already matched. The caller is responsible for
+    /// [`Flasher::enable_flash`] beforehand and for the matching flash-finish afterwards, since
+    /// those are done once per flashing session.
+    fn write_segment(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        compress: bool,
+        force: bool,
+    ) -> Result<bool, Error> {
+        let write_size = self.flash_write_size();
+
+        let erase_size = match self.chip {
+            Chip::Esp8266 => get_erase_size(addr as usize, data.len()) as u32,
+            _ => data.len() as u32,
+        };
+
+        // The device erases whole sectors, so everything from `data.len()` up to the next
+        // sector boundary reads back as 0xff. Verify over that sector-granular extent rather
+        // than the write-block size, which (when stubbed) is larger than a sector and would
+        // reach past the erased region into stale flash.
+        let verify_len = (data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE;
+        let mut padded = data.to_vec();
+        padded.resize(verify_len, 0xff);
+        let local_md5 = *md5::compute(&padded);
+
+        let md5_capable = self.supports_md5();
+
+        if md5_capable && !force && self.flash_md5(addr, verify_len as u32)? == local_md5 {
+            let pb = segment_progress_bar(0);
+            pb.finish_with_message(format!("segment 0x{:X} unchanged, skipping", addr));
+            return Ok(false);
+        }
+
+        if compress {
+            let compressed = compress_segment(data)?;
+            let block_count = (compressed.len() + write_size - 1) / write_size;
+
+            // `size` stays the *uncompressed* length so the device erases the right amount.
             self.begin_command(
-                Command::FlashBegin,
+                Command::FlashDeflBegin,
                 erase_size,
                 block_count as u32,
-                FLASH_WRITE_SIZE as u32,
+                write_size as u32,
                 addr,
             )?;
 
-            let chunks = segment.data.chunks(FLASH_WRITE_SIZE);
+            let chunks = compressed.chunks(write_size);
+            let pb_chunk = segment_progress_bar(block_count as u64);
 
-            let (_, chunk_size) = chunks.size_hint();
-            let chunk_size = chunk_size.unwrap_or(0) as u64;
-            let pb_chunk = ProgressBar::new(chunk_size);
-            pb_chunk.set_style(
-                ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-                    .progress_chars("#>-"),
-            );
+            for (i, block) in chunks.enumerate() {
+                pb_chunk.set_message(format!("segment 0x{:X} writing chunks", addr));
+                // Deflate blocks are sent verbatim: no 0xff padding, checksum over the
+                // compressed bytes only.
+                self.block_command(Command::FlashDeflData, block, 0, 0, i as u32)?;
+                pb_chunk.inc(1);
+            }
+
+            pb_chunk.finish_with_message(format!("segment 0x{:X}", addr));
+        } else {
+            let block_count = (data.len() + write_size - 1) / write_size;
+
+            self.begin_command(
+                Command::FlashBegin,
+                erase_size,
+                block_count as u32,
+                write_size as u32,
+                addr,
+            )?;
+
+            let chunks = data.chunks(write_size);
+            let pb_chunk = segment_progress_bar(block_count as u64);
 
             for (i, block) in chunks.enumerate() {
                 pb_chunk.set_message(format!("segment 0x{:X} writing chunks", addr));
-                let block_padding = FLASH_WRITE_SIZE - block.len();
+                let block_padding = write_size - block.len();
                 self.block_command(Command::FlashData, block, block_padding, 0xff, i as u32)?;
                 pb_chunk.inc(1);
             }
@@ -604,13 +968,137 @@ impl Flasher {
             pb_chunk.finish_with_message(format!("segment 0x{:X}", addr));
         }
 
-        self.flash_finish(false)?;
+        // Re-read the region back and fail loudly if it does not match what we wrote. This runs
+        // even when `force` is set: a forced flash is exactly when confirmation matters most.
+        // Only skipped on loaders without the md5 command.
+        if md5_capable && self.flash_md5(addr, verify_len as u32)? != local_md5 {
+            return Err(Error::VerifyFailed);
+        }
 
-        self.connection.reset()?;
+        Ok(true)
+    }
+
+    /// Read `size` bytes of flash contents starting at `offset` off the device.
+    ///
+    /// Useful for verifying a freshly written image, extracting existing firmware, or
+    /// dumping a partition table.
+    pub fn read_flash(&mut self, offset: u32, size: u32) -> Result<Vec<u8>, Error> {
+        let block_count =
+            (size as usize + MAX_SPI_READ_SIZE - 1) / MAX_SPI_READ_SIZE;
+        let pb = segment_progress_bar(block_count as u64);
+        pb.set_message(format!("reading flash 0x{:X}", offset));
+
+        let mut data = Vec::with_capacity(size as usize);
+        let mut addr = offset;
+        let mut remaining = size as usize;
+        while remaining > 0 {
+            let len = remaining.min(MAX_SPI_READ_SIZE);
+            let block = self.spi_read_block(addr, len)?;
+            data.extend_from_slice(&block);
+            addr += len as u32;
+            remaining -= len;
+            pb.inc(1);
+        }
 
+        pb.finish_with_message(format!("read flash 0x{:X}", offset));
+        Ok(data)
+    }
+
+    /// Read `size` bytes of flash starting at `offset` and write them to `path`.
+    pub fn dump_flash(
+        &mut self,
+        offset: u32,
+        size: u32,
+        path: impl AsRef<Path>,
+    ) -> Result<(), Error> {
+        let data = self.read_flash(offset, size)?;
+        std::fs::write(path, data)?;
         Ok(())
     }
 
+    /// Erase `size` bytes of flash starting at `offset`.
+    ///
+    /// When a stub is running the dedicated `EraseRegion` command is used. On the bare ROM we
+    /// fall back to a classic bit-bang loop: write-enable, issue a block/sector erase opcode
+    /// over SPI, then poll the status register until the write-in-progress bit clears.
+    pub fn erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        // Align the request to sector boundaries so whole sectors are erased.
+        let sector = FLASH_SECTOR_SIZE as u32;
+        let start = offset - offset % sector;
+        let end = (offset + size + sector - 1) / sector * sector;
+
+        if self.stub {
+            let params = EraseRegionParams {
+                offset: start,
+                size: end - start,
+            };
+            return self.connection.with_timeout(
+                Command::EraseRegion.timeout_for_size(end - start),
+                |connection| connection.command(Command::EraseRegion as u8, bytes_of(&params), 0),
+            );
+        }
+
+        // Re-attach SPI before bit-banging, as `load_elf_to_flash` and `write_bins_to_flash` do,
+        // rather than relying on the attach that `connect` happened to do during autodetect.
+        self.enable_flash(self.spi_params)?;
+
+        let block = FLASH_BLOCK_ERASE_SIZE as u32;
+        let pb = segment_progress_bar(((end - start) / sector) as u64);
+        pb.set_message(format!("erasing 0x{:X}", start));
+
+        let mut addr = start;
+        while addr < end {
+            // Use the faster 64KB block erase whenever the address is aligned and fits.
+            let (opcode, step) = if addr % block == 0 && end - addr >= block {
+                (SPI_FLASH_BLOCK_ERASE, block)
+            } else {
+                (SPI_FLASH_SECTOR_ERASE, sector)
+            };
+
+            self.spi_write_enable()?;
+            let bytes = addr.to_be_bytes();
+            self.spi_command(opcode, &bytes[1..4], 0)?;
+            self.spi_wait_done()?;
+
+            pb.inc((step / sector) as u64);
+            addr += step;
+        }
+
+        pb.finish_with_message(format!("erased 0x{:X}", start));
+        Ok(())
+    }
+
+    /// Erase the entire flash chip.
+    pub fn erase_flash(&mut self) -> Result<(), Error> {
+        if self.stub {
+            self.connection
+                .with_timeout(Command::EraseFlash.timeout_for_size(self.flash_size.size()), |connection| {
+                    connection.command(Command::EraseFlash as u8, &[], 0)
+                })
+        } else {
+            self.erase_region(0, self.flash_size.size())
+        }
+    }
+
+    /// Issue the SPI write-enable opcode, required before an erase or program operation.
+    fn spi_write_enable(&mut self) -> Result<(), Error> {
+        self.spi_command(SPI_FLASH_WRITE_ENABLE, &[], 0)?;
+        Ok(())
+    }
+
+    /// Poll the flash status register until the write-in-progress bit clears.
+    fn spi_wait_done(&mut self) -> Result<(), Error> {
+        for _ in 0..100 {
+            let status = self.spi_command(SPI_FLASH_READ_STATUS, &[], 8)?;
+            if status & 0x1 == 0 {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        Err(Error::Timeout)
+    }
+
     pub fn change_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
         let new_speed = (speed.speed() as u32).to_le_bytes();
         let old_speed = 0u32.to_le_bytes();
@@ -630,6 +1118,25 @@ impl Flasher {
     }
 }
 
+/// zlib-compress a segment's data at the default compression level, ready to be streamed
+/// through the `FlashDefl*` command trio.
+fn compress_segment(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Build the per-segment chunk progress bar shared by the flash write paths.
+fn segment_progress_bar(len: u64) -> ProgressBar {
+    let pb_chunk = ProgressBar::new(len);
+    pb_chunk.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
+            .progress_chars("#>-"),
+    );
+    pb_chunk
+}
+
 fn get_erase_size(offset: usize, size: usize) -> usize {
     let sector_count = (size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
     let start_sector = offset / FLASH_SECTOR_SIZE;